@@ -0,0 +1,906 @@
+//
+// (c) 2020 Tijn Porcelijn
+//
+// Simple MPEG2 Transport Stream demuxer
+// - start with PAT handler listening to PID=0
+// - from PAT create PMT listener to PID specified in PAT
+// - from PMT create PES listeners for its elementary streams (AAC, h264,
+//   or any other stream type, carried through as opaque payload)
+//
+// See: https://en.wikipedia.org/wiki/MPEG_transport_stream
+//
+
+pub mod mp4;
+pub mod sync;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const PACKET_SIZE: usize = 188;
+pub type Packet = [u8; PACKET_SIZE];
+
+// no significant performance impact beyond 4kB chunks
+const BUFFER_SIZE: usize = 32 * PACKET_SIZE;
+
+// Reserved PID for null-stuffing packets (ISO/IEC 13818-1 2.4.3.2).
+const NULL_PID: u16 = 0x1FFF;
+
+// Errors from parsing untrusted/corrupt TS, PSI or PES data. None of these
+// are fatal to the demuxer as a whole: callers log and skip the offending
+// packet or section rather than aborting the whole stream.
+#[derive(Debug)]
+pub enum DemuxError {
+    BadSyncByte(u8),
+    ShortPacket,
+    InvalidAdaptationField,
+    ContinuityDiscontinuity { pid: u16, expected: u8, got: u8 },
+    UnknownPid(u16),
+    MalformedPsi,
+    MalformedPes,
+}
+
+impl fmt::Display for DemuxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DemuxError::BadSyncByte(byte) =>
+                write!(f, "expected sync byte 0x47, got {:#04x}", byte),
+            DemuxError::ShortPacket =>
+                write!(f, "packet too short for the field being parsed"),
+            DemuxError::InvalidAdaptationField =>
+                write!(f, "invalid or inconsistent adaptation field"),
+            DemuxError::ContinuityDiscontinuity { pid, expected, got } =>
+                write!(f, "continuity counter discontinuity on pid {}: expected {}, got {}",
+                       pid, expected, got),
+            DemuxError::UnknownPid(pid) =>
+                write!(f, "packet for unregistered pid {}", pid),
+            DemuxError::MalformedPsi =>
+                write!(f, "malformed PSI section"),
+            DemuxError::MalformedPes =>
+                write!(f, "malformed PES header"),
+        }
+    }
+}
+
+impl std::error::Error for DemuxError {}
+
+fn get_pid(packet: &Packet) -> u16 {
+    let pid = ((packet[1] & 0x1f) as u16) << 8 | (packet[2] as u16);
+    pid
+}
+
+fn get_payload_offset(packet: &Packet) -> Result<usize, DemuxError> {
+    let adaptation_field_control = (packet[3] & 0x30) >> 4;
+    match adaptation_field_control {
+        0b01 => Ok(4), // only payload, no adaptation field
+        0b10 => Ok(188), // only adaptation field, no payload
+        0b11 => { // adaptation field followed by payload
+            let adaptation_field_length = packet[4] as usize;
+            if adaptation_field_length + 5 > packet.len() {
+                return Err(DemuxError::InvalidAdaptationField);
+            }
+            Ok(adaptation_field_length + 5)
+        }
+        _ => Err(DemuxError::InvalidAdaptationField) // reserved value
+    }
+}
+
+fn get_pusi(packet: &Packet) -> bool {
+    let pusi = packet[1] & 0x40;
+    pusi != 0
+}
+
+fn get_continuity_counter(packet: &Packet) -> u8 {
+    let continuity_counter = packet[3] & 0x0f;
+    continuity_counter
+}
+
+// Whether the adaptation field's discontinuity_indicator is set, meaning a
+// break in the continuity counter sequence here is expected, not corruption.
+fn get_discontinuity_indicator(packet: &Packet) -> bool {
+    let adaptation_field_control = (packet[3] & 0x30) >> 4;
+    if adaptation_field_control & 0b10 == 0 || packet[4] == 0 {
+        return false;
+    }
+    packet[5] & 0x80 != 0
+}
+
+fn get_pes_header_size(pes: &[u8]) -> Result<usize, DemuxError> {
+    if pes.len() < 9 {
+        return Err(DemuxError::ShortPacket);
+    }
+    if pes[0] != 0x00 {
+        return Err(DemuxError::MalformedPes);
+    }
+//  assert_eq!(pes[1], 0x00);
+//  assert_eq!(pes[2], 0x01);
+    let pes_header_length = pes[8] as usize;
+    Ok(9 + pes_header_length)
+}
+
+// A 27 MHz clock reference, the common unit of PCR/PTS/DTS once decoded.
+// PTS/DTS tick at 90 kHz, so they are normalized here by the 300x factor
+// that separates the two clocks (27 MHz = 300 * 90 kHz).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockRef(u64);
+
+impl ClockRef {
+    fn from_90khz(ticks: u64) -> ClockRef {
+        ClockRef(ticks * 300)
+    }
+
+    fn from_pcr(base: u64, extension: u16) -> ClockRef {
+        ClockRef(base * 300 + extension as u64)
+    }
+}
+
+// Reassembles a 33-bit PTS/DTS value from the 5-byte encoding used in the
+// PES optional header, discarding the marker bits interleaved with it.
+fn parse_timestamp_90khz(bytes: &[u8]) -> u64 {
+    assert_eq!(bytes.len(), 5);
+    ((bytes[0] as u64 >> 1) & 0x7) << 30
+        | (bytes[1] as u64) << 22
+        | ((bytes[2] as u64 >> 1) & 0x7f) << 15
+        | (bytes[3] as u64) << 7
+        | (bytes[4] as u64 >> 1)
+}
+
+// Extracts PTS/DTS from the PES optional header, given `PTS_DTS_flags`
+// (the top two bits of byte 7): 0b10 is PTS only, 0b11 is PTS then DTS.
+fn get_pts_dts(pes: &[u8]) -> Result<(Option<ClockRef>, Option<ClockRef>), DemuxError> {
+    if pes.len() < 9 {
+        return Err(DemuxError::ShortPacket);
+    }
+    let pts_dts_flags = (pes[7] & 0xC0) >> 6;
+    match pts_dts_flags {
+        0b10 => {
+            if pes.len() < 14 {
+                return Err(DemuxError::ShortPacket);
+            }
+            let pts = ClockRef::from_90khz(parse_timestamp_90khz(&pes[9..14]));
+            Ok((Some(pts), None))
+        }
+        0b11 => {
+            if pes.len() < 19 {
+                return Err(DemuxError::ShortPacket);
+            }
+            let pts = ClockRef::from_90khz(parse_timestamp_90khz(&pes[9..14]));
+            let dts = ClockRef::from_90khz(parse_timestamp_90khz(&pes[14..19]));
+            Ok((Some(pts), Some(dts)))
+        }
+        _ => Ok((None, None))
+    }
+}
+
+// Extracts the program clock reference from the adaptation field, if the
+// packet carries one (adaptation field flags bit 0x10 = PCR_flag).
+fn get_pcr(packet: &Packet) -> Option<ClockRef> {
+    let adaptation_field_control = (packet[3] & 0x30) >> 4;
+    if adaptation_field_control & 0b10 == 0 {
+        return None;
+    }
+    let adaptation_field_length = packet[4] as usize;
+    // flags byte + 6-byte PCR
+    if adaptation_field_length < 7 {
+        return None;
+    }
+    let adaptation_field_flags = packet[5];
+    let pcr_flag = adaptation_field_flags & 0x10;
+    if pcr_flag == 0 {
+        return None;
+    }
+
+    let base = (packet[6] as u64) << 25
+        | (packet[7] as u64) << 17
+        | (packet[8] as u64) << 9
+        | (packet[9] as u64) << 1
+        | (packet[10] as u64 >> 7);
+    let extension = ((packet[10] as u16 & 1) << 8) | packet[11] as u16;
+
+    Some(ClockRef::from_pcr(base, extension))
+}
+
+trait PacketProcessor {
+    fn process(&mut self, packet: &Packet) -> Result<UpdateProgramMap, DemuxError>;
+}
+
+type ProgramMap = HashMap<u16, Box<dyn PacketProcessor>>;
+type UpdateProgramMap = Box<dyn Fn(&mut ProgramMap)>;
+fn no_update() -> UpdateProgramMap {
+    Box::new(|_programs: &mut ProgramMap| ())
+                            as Box<dyn Fn(&mut ProgramMap)>
+}
+
+// Header of the PES packet that triggered `begin_packet`, carrying the
+// presentation/decode timestamps when the PES optional header has them.
+pub struct PesHeader {
+    pts: Option<ClockRef>,
+    dts: Option<ClockRef>,
+}
+
+// An elementary-stream consumer receives the demuxed payload of a single
+// PID without knowing anything about where it ends up: a file, a decoder,
+// a network socket, ... `begin_packet`/`continue_packet`/`end_packet`
+// mirror PES packet boundaries, which are marked by PUSI in the TS header.
+pub trait ElementaryStreamConsumer {
+    fn start_stream(&mut self, pid: u16, stream_type: u8);
+    fn begin_packet(&mut self, header: PesHeader);
+    fn continue_packet(&mut self, data: &[u8]);
+    fn end_packet(&mut self);
+
+    // Called whenever this PID's adaptation field carries a program clock
+    // reference. Most consumers don't need it, hence the default no-op.
+    fn on_pcr(&mut self, _pcr: ClockRef) {
+    }
+
+    // Called once the PID's source of packets goes away (e.g. end of
+    // file), after any in-flight `end_packet`. Consumers that hold onto a
+    // sample until the *next* one arrives (to learn its duration), like
+    // `Mp4Consumer`, override this to flush what's left; most don't need it.
+    fn finish(&mut self) {
+    }
+}
+
+// Builds a consumer for a given elementary stream. Registered once per
+// (pid, stream_type) the first time the PMT announces it.
+pub type ConsumerFactory = Box<dyn Fn(u16, u8) -> Box<dyn ElementaryStreamConsumer>>;
+
+// Writes the raw elementary stream to `elephants-{pid}.{ext}`, the
+// original built-in behaviour, now expressed as just one possible
+// `ElementaryStreamConsumer`.
+struct FileConsumer {
+    writer: Box<dyn Write>
+}
+
+impl FileConsumer {
+    fn new(pid: u16, stream_type: u8) -> FileConsumer {
+        let extension = match stream_type {
+            0x0F => "aac",
+            0x1B => "avc",
+            _ => "es" // unrecognized stream type: dump it opaquely rather than reject it
+        };
+
+        let filename = format!("elephants-{}.{}", pid, extension);
+        println!("  ES: stream_type={}, pid={} -> {}", stream_type, pid, filename);
+
+        let writer = File::create(&filename[..]).unwrap_or_else(|_| {
+            panic!("Failed to create: {}", filename);
+        });
+        let writer = BufWriter::with_capacity(BUFFER_SIZE, writer);
+        FileConsumer { writer: Box::new(writer) }
+    }
+}
+
+impl ElementaryStreamConsumer for FileConsumer {
+    fn start_stream(&mut self, pid: u16, stream_type: u8) {
+        let description = match stream_type {
+            0x0F => "ISO/IEC 13818-7 ADTS AAC / MPEG-2 lower bit-rate audio",
+            0x1B => "ISO/IEC 14496-10 / H.264 lower bit-rate video",
+            _  => "unrecognized stream type, carried through opaquely"
+        };
+        println!("  ES: stream_type={} ({}), pid={}", stream_type, description, pid);
+    }
+
+    fn begin_packet(&mut self, _header: PesHeader) {
+    }
+
+    fn on_pcr(&mut self, _pcr: ClockRef) {
+    }
+
+    fn continue_packet(&mut self, data: &[u8]) {
+        self.writer.write_all(data).unwrap_or_else(|e| {
+            panic!("Failed to write elementary stream data: {}", e);
+        });
+    }
+
+    fn end_packet(&mut self) {
+    }
+}
+
+pub fn file_consumer_factory() -> ConsumerFactory {
+    Box::new(|pid: u16, stream_type: u8| {
+        Box::new(FileConsumer::new(pid, stream_type)) as Box<dyn ElementaryStreamConsumer>
+    })
+}
+
+// H.264 elementary streams are Annex B: NAL units back to back, each
+// preceded by a 00 00 01 or 00 00 00 01 start code. Returns the position
+// and length of each start code; `split_annex_b` turns these into the NAL
+// units themselves, start codes excluded.
+fn find_start_codes(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut start_codes = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            start_codes.push((i, 3));
+            i += 3;
+        } else if i + 4 <= data.len() && data[i] == 0 && data[i + 1] == 0
+            && data[i + 2] == 0 && data[i + 3] == 1 {
+            start_codes.push((i, 4));
+            i += 4;
+        } else {
+            i += 1;
+        }
+    }
+    start_codes
+}
+
+fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let start_codes = find_start_codes(data);
+    let mut nals = Vec::with_capacity(start_codes.len());
+    for (index, &(pos, code_len)) in start_codes.iter().enumerate() {
+        let start = pos + code_len;
+        let end = start_codes.get(index + 1).map_or(data.len(), |&(next_pos, _)| next_pos);
+        if end > start {
+            nals.push(&data[start .. end]);
+        }
+    }
+    nals
+}
+
+// Remuxes the elementary stream into an `.mp4` file instead of writing it
+// raw: one sample per PES access unit, fed to a shared `FragmentedMp4Writer`
+// so that the AVC and AAC tracks of one program end up in the same file.
+struct Mp4Consumer {
+    stream_type: u8,
+    writer: Rc<RefCell<mp4::FragmentedMp4Writer<BufWriter<File>>>>,
+    track_id: u32,
+    configured: bool,
+    buffer: Vec<u8>,
+    current_pts: Option<ClockRef>,
+    pending: Option<(Vec<u8>, bool, ClockRef)>,
+    last_duration: u32,
+}
+
+impl Mp4Consumer {
+    fn new(stream_type: u8, writer: Rc<RefCell<mp4::FragmentedMp4Writer<BufWriter<File>>>>) -> Mp4Consumer {
+        let track_id = writer.borrow_mut().register_track(90_000);
+        Mp4Consumer {
+            stream_type, writer, track_id,
+            configured: false,
+            buffer: Vec::new(),
+            current_pts: None,
+            pending: None,
+            last_duration: 0,
+        }
+    }
+
+    fn flush_sample(&mut self, data: Vec<u8>, is_keyframe: bool, duration: u32) {
+        let sample = mp4::Sample { data, duration, is_keyframe };
+        self.writer.borrow_mut().write_sample(self.track_id, sample).unwrap_or_else(|e| {
+            panic!("Failed to write MP4 sample: {}", e);
+        });
+    }
+
+    fn handle_avc_au(&mut self, au: Vec<u8>, pts: ClockRef) {
+        let mut sps = None;
+        let mut pps = None;
+        let mut is_keyframe = false;
+        let mut length_prefixed = Vec::with_capacity(au.len());
+        for nal in split_annex_b(&au) {
+            let nal_type = nal[0] & 0x1F;
+            match nal_type {
+                7 if sps.is_none() => sps = Some(nal.to_vec()),
+                8 if pps.is_none() => pps = Some(nal.to_vec()),
+                5 => is_keyframe = true,
+                _ => {}
+            }
+            length_prefixed.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            length_prefixed.extend_from_slice(nal);
+        }
+
+        if !self.configured {
+            if let (Some(sps), Some(pps)) = (sps, pps) {
+                let config = mp4::TrackConfig::Avc(mp4::AvcConfig::from_sps_pps(sps, pps));
+                self.writer.borrow_mut().set_track_config(self.track_id, config).unwrap_or_else(|e| {
+                    panic!("Failed to write MP4 header: {}", e);
+                });
+                self.configured = true;
+            }
+        }
+
+        self.pending = Some((length_prefixed, is_keyframe, pts));
+    }
+
+    fn handle_aac_au(&mut self, au: Vec<u8>, pts: ClockRef) {
+        if au.len() < 7 {
+            return;
+        }
+        // protection_absent: 1 means no CRC (7-byte header), 0 means CRC present (9 bytes)
+        let header_len = if au[1] & 0x01 == 1 { 7 } else { 9 };
+
+        if !self.configured {
+            if let Some(config) = mp4::AacConfig::from_adts_header(&au) {
+                self.writer.borrow_mut().set_track_config(self.track_id, mp4::TrackConfig::Aac(config))
+                    .unwrap_or_else(|e| panic!("Failed to write MP4 header: {}", e));
+                self.configured = true;
+            }
+        }
+
+        self.pending = Some((au[header_len.min(au.len())..].to_vec(), true, pts));
+    }
+}
+
+impl ElementaryStreamConsumer for Mp4Consumer {
+    fn start_stream(&mut self, _pid: u16, _stream_type: u8) {
+    }
+
+    fn begin_packet(&mut self, header: PesHeader) {
+        let pts = header.pts.or(header.dts);
+        if let (Some((data, is_keyframe, prev_pts)), Some(pts)) = (self.pending.take(), pts) {
+            let delta_90khz = (pts.0.saturating_sub(prev_pts.0) / 300) as u32;
+            let duration = if delta_90khz > 0 { delta_90khz } else { self.last_duration.max(1) };
+            self.last_duration = duration;
+            self.flush_sample(data, is_keyframe, duration);
+        }
+        self.current_pts = pts;
+        self.buffer.clear();
+    }
+
+    fn continue_packet(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn end_packet(&mut self) {
+        let pts = match self.current_pts {
+            Some(pts) => pts,
+            None => return
+        };
+        let au = std::mem::take(&mut self.buffer);
+        match self.stream_type {
+            0x1B => self.handle_avc_au(au, pts),
+            0x0F => self.handle_aac_au(au, pts),
+            _ => {}
+        }
+    }
+
+    // `begin_packet` only flushes a sample once the *next* one arrives (to
+    // learn its duration from the PTS delta); without this, the very last
+    // sample of the stream would stay in `self.pending` forever.
+    fn finish(&mut self) {
+        if let Some((data, is_keyframe, _pts)) = self.pending.take() {
+            let duration = self.last_duration.max(1);
+            self.flush_sample(data, is_keyframe, duration);
+        }
+    }
+}
+
+pub fn mp4_consumer_factory(filename: &str) -> ConsumerFactory {
+    let file = File::create(filename).unwrap_or_else(|e| {
+        panic!("Failed to create {}: {}", filename, e);
+    });
+    let writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+    let writer = Rc::new(RefCell::new(mp4::FragmentedMp4Writer::new(writer)));
+    Box::new(move |_pid: u16, stream_type: u8| {
+        Box::new(Mp4Consumer::new(stream_type, Rc::clone(&writer))) as Box<dyn ElementaryStreamConsumer>
+    })
+}
+
+struct Program {
+    pid: u16,
+    continuity_counter: u8,
+    in_packet: bool,
+    consumer: Box<dyn ElementaryStreamConsumer>
+}
+
+impl Program {
+    fn new(pid: u16, stream_type: u8, factory: &ConsumerFactory) -> Program {
+        let mut consumer = factory(pid, stream_type);
+        consumer.start_stream(pid, stream_type);
+        Program { pid, continuity_counter: 0, in_packet: false, consumer }
+    }
+}
+
+impl PacketProcessor for Program {
+    fn process(&mut self, packet: &Packet) -> Result<UpdateProgramMap, DemuxError> {
+        if packet[0] != 0x47 {
+            return Err(DemuxError::BadSyncByte(packet[0]));
+        }
+
+        // Check the continuity counter, tolerating a deliberate jump
+        // signalled by the adaptation field's discontinuity_indicator.
+        let expected = self.continuity_counter;
+        let got = get_continuity_counter(packet);
+        let discontinuous = expected != got && !get_discontinuity_indicator(packet);
+        self.continuity_counter = (got + 1) % 16;
+        if discontinuous {
+            return Err(DemuxError::ContinuityDiscontinuity { pid: self.pid, expected, got });
+        }
+
+        // skip adaptation field
+        let mut offset: usize = get_payload_offset(packet)?;
+
+        // Only extract the PCR once the adaptation field has been
+        // validated above, so a malformed packet can't still leak a bogus
+        // clock reference to the consumer before being rejected.
+        if let Some(pcr) = get_pcr(packet) {
+            self.consumer.on_pcr(pcr);
+        }
+
+        // PUSI marks the start of a new PES packet: close the previous one
+        // (if any) before skipping its header and opening the new one.
+        if get_pusi(packet) {
+            if self.in_packet {
+                self.consumer.end_packet();
+            }
+            let (pts, dts) = get_pts_dts(&packet[offset..])?;
+            offset += get_pes_header_size(&packet[offset..])?;
+            self.consumer.begin_packet(PesHeader { pts, dts });
+            self.in_packet = true;
+        }
+
+        self.consumer.continue_packet(&packet[offset..]);
+
+        Ok(no_update())
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        if self.in_packet {
+            self.consumer.end_packet();
+        }
+        self.consumer.finish();
+    }
+}
+
+type TableData<'a> = &'a [u8];
+type TableProcessor = Box<dyn Fn(TableData) -> Result<UpdateProgramMap, DemuxError>>;
+
+fn program_association_table(table_data: TableData, factory: Rc<ConsumerFactory>) -> Result<UpdateProgramMap, DemuxError> {
+    if table_data.len() != 4 {
+        return Err(DemuxError::MalformedPsi);
+    }
+    let program_number = ((table_data[0] as u16) << 8)
+        | (table_data[1] as u16);
+    if table_data[2] & 0b11100000 != 0b11100000 { // reserved bits
+        return Err(DemuxError::MalformedPsi);
+    }
+    let program_pid = (((table_data[2] & 0x1F) as u16) << 8)
+        | (table_data[3] as u16);
+
+    Ok(Box::new(move |programs: &mut ProgramMap| {
+        let factory = Rc::clone(&factory);
+        programs.entry(program_pid).or_insert_with(|| {
+            println!(" PAT: number={}, PMT pid={}", program_number, program_pid);
+            let pmt = Box::new(move |table_data: TableData| program_map_table(table_data, Rc::clone(&factory)));
+            let psi = ProgramSpecificInformation::new(pmt);
+            Box::new(psi)
+        });
+    }))
+}
+
+fn program_map_table(table_data: TableData, factory: Rc<ConsumerFactory>) -> Result<UpdateProgramMap, DemuxError> {
+    if table_data.len() <= 4 {
+        return Err(DemuxError::MalformedPsi);
+    }
+    if table_data[0] & 0b11100000 != 0b11100000 { // reserved bits
+        return Err(DemuxError::MalformedPsi);
+    }
+    let _pcr_pid = (((table_data[0] & 0x1F) as u16) << 8)
+        | (table_data[1] as u16);
+    if table_data[2] & 0b11111100 != 0b11110000 { // 4x1 reserved bits + 2x0 unused
+        return Err(DemuxError::MalformedPsi);
+    }
+    let program_info_length = (((table_data[2] & 0b00000011) as u16) << 8)
+        | (table_data[3] as u16);
+    let program_info_length = program_info_length  as usize;
+    if program_info_length >= table_data.len() {
+        return Err(DemuxError::MalformedPsi);
+    }
+    // skip program_descriptor [..]
+//  println!(" PMT: pcr_pid={}, program_info_length={}", _pcr_pid, program_info_length);
+
+    let mut es_info_data = &table_data[4 + program_info_length .. ];
+
+    let mut add_programs = no_update();
+    while es_info_data.len() >= 5
+    {
+        // Elementary stream specific data
+        let stream_type = es_info_data[0];
+        if es_info_data[1] & 0b11100000 != 0b11100000 { // reserved bits
+            return Err(DemuxError::MalformedPsi);
+        }
+        let es_pid = (((es_info_data[1] & 0x1F) as u16) << 8)
+            | (es_info_data[2] as u16);
+        if es_info_data[3] & 0b11111100 != 0b11110000 { // 4x1 reserved bits + 2x0 unused
+            return Err(DemuxError::MalformedPsi);
+        }
+        let es_info_length = (((es_info_data[3] & 0b00000011) as u16) << 8)
+            | (es_info_data[4] as u16);
+        let es_info_length = es_info_length as usize;
+        let factory_for_es = Rc::clone(&factory);
+
+        add_programs = Box::new(move |programs: &mut ProgramMap| {
+            add_programs(programs);
+
+            let factory_for_es = Rc::clone(&factory_for_es);
+            programs.entry(es_pid).or_insert_with(|| {
+                let program = Program::new(es_pid, stream_type, &factory_for_es);
+                Box::new(program)
+            });
+        });
+
+        if 5 + es_info_length > es_info_data.len() {
+            return Err(DemuxError::MalformedPsi);
+        }
+        es_info_data = &es_info_data[5 + es_info_length ..];
+    }
+    if !es_info_data.is_empty() {
+        return Err(DemuxError::MalformedPsi);
+    }
+    Ok(add_programs)
+}
+
+// CRC-32/MPEG-2 as used to validate PSI sections: poly 0x04C11DB7,
+// init 0xFFFFFFFF, no input/output reflection, no final xor.
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04C11DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn combine_updates(first: UpdateProgramMap, second: UpdateProgramMap) -> UpdateProgramMap {
+    Box::new(move |programs: &mut ProgramMap| {
+        first(programs);
+        second(programs);
+    })
+}
+
+// A PAT/PMT section can span several 188-byte TS packets. This buffers the
+// bytes of the section currently in flight (starting at `table_id`) across
+// packets until `section_length` bytes have been collected, validates the
+// trailing CRC-32, and only then hands it to the `TableProcessor`.
+struct ProgramSpecificInformation {
+    table_processor: TableProcessor,
+    section: Vec<u8>,
+}
+
+impl ProgramSpecificInformation {
+    fn new(table_processor: TableProcessor) -> ProgramSpecificInformation {
+        ProgramSpecificInformation { table_processor, section: Vec::new() }
+    }
+
+    fn new_pat(factory: Rc<ConsumerFactory>) -> Box<dyn PacketProcessor> {
+        Box::new(ProgramSpecificInformation::new(Box::new(move |table_data: TableData| {
+            program_association_table(table_data, Rc::clone(&factory))
+        })))
+    }
+
+    // Length of the complete section (header + table syntax section + CRC),
+    // once enough bytes have arrived to read `section_length`.
+    fn wanted_len(&self) -> Result<Option<usize>, DemuxError> {
+        if self.section.len() < 3 {
+            return Ok(None);
+        }
+        if self.section[0] == 0xFF {
+            return Err(DemuxError::MalformedPsi);
+        }
+        // section syntax indicator = 1, private bit = 0, reserved bits = 0x3
+        if self.section[1] & 0b11110000 != 0b10110000 {
+            return Err(DemuxError::MalformedPsi);
+        }
+        let section_length = ((self.section[1] as u16) & 0x000F) << 8
+            | (self.section[2] as u16);
+        if section_length >= 1021 {
+            return Err(DemuxError::MalformedPsi);
+        }
+        // Table syntax section (5 bytes) + CRC-32 (4 bytes), at minimum.
+        if section_length < 9 {
+            return Err(DemuxError::MalformedPsi);
+        }
+        Ok(Some(3 + section_length as usize))
+    }
+
+    // If the buffered bytes now make up a complete section, validate and
+    // consume it, returning the resulting `UpdateProgramMap`.
+    fn take_complete_section(&mut self) -> Result<Option<UpdateProgramMap>, DemuxError> {
+        let wanted_len = match self.wanted_len()? {
+            Some(wanted_len) => wanted_len,
+            None => return Ok(None)
+        };
+        if self.section.len() < wanted_len {
+            return Ok(None);
+        }
+        let section: Vec<u8> = self.section.drain(..wanted_len).collect();
+        // A 0xFF table_id after a section is stuffing up to the end of the
+        // packet, not the start of another section in flight.
+        if self.section.first() == Some(&0xFF) {
+            self.section.clear();
+        }
+
+        let computed_crc32 = crc32_mpeg2(&section[.. wanted_len - 4]);
+        let stored_crc32 = (section[wanted_len - 4] as u32) << 24
+            | (section[wanted_len - 3] as u32) << 16
+            | (section[wanted_len - 2] as u32) << 8
+            | (section[wanted_len - 1] as u32);
+        if computed_crc32 != stored_crc32 {
+            eprintln!("  PSI: dropping section with bad CRC (expected {:#010x}, got {:#010x})",
+                      stored_crc32, computed_crc32);
+            return Ok(Some(no_update()));
+        }
+
+        let table_syntax_section = &section[3 ..];
+        let section_length = wanted_len - 3;
+
+        let _table_id_extension = (table_syntax_section[0] as u16) << 8 |
+                                  (table_syntax_section[1] as u16);
+        if table_syntax_section[2] & 0b11000000 != 0b11000000 {
+            return Err(DemuxError::MalformedPsi);
+        }
+        let _syntax_version_number = (table_syntax_section[2] & 0b00111110) >> 1;
+        let current_indicator = (table_syntax_section[2] & 0x00000001) == 1;
+        if !current_indicator {
+            // Describes a table version that isn't active yet; nothing to do.
+            return Ok(Some(no_update()));
+        }
+        let _section_number = table_syntax_section[3];
+        let _last_section_number = table_syntax_section[4];
+
+        let table_data = &table_syntax_section[5 .. section_length - 4];
+
+        Ok(Some((self.table_processor)(table_data)?))
+    }
+}
+
+impl PacketProcessor for ProgramSpecificInformation {
+    fn process(&mut self, packet: &Packet) -> Result<UpdateProgramMap, DemuxError> {
+        let offset: usize = get_payload_offset(packet)?;
+        let mut data = &packet[offset ..];
+        let mut update = no_update();
+
+        if get_pusi(packet) {
+            if data.is_empty() {
+                return Err(DemuxError::ShortPacket);
+            }
+            let pointer_field = data[0] as usize;
+            data = &data[1 ..];
+            if pointer_field > data.len() {
+                return Err(DemuxError::ShortPacket);
+            }
+            let (section_remainder, next_section) = data.split_at(pointer_field);
+
+            // Bytes before the pointed-to section either finish a section
+            // already in flight, or are plain 0xFF stuffing if none is.
+            if self.section.is_empty() {
+                if section_remainder.iter().any(|&filler| filler != 0xFF) {
+                    return Err(DemuxError::MalformedPsi);
+                }
+            } else {
+                self.section.extend_from_slice(section_remainder);
+                if let Some(u) = self.take_complete_section()? {
+                    update = combine_updates(update, u);
+                }
+            }
+
+            self.section.clear();
+            data = next_section;
+        }
+
+        self.section.extend_from_slice(data);
+        if let Some(u) = self.take_complete_section()? {
+            update = combine_updates(update, u);
+        }
+
+        Ok(update)
+    }
+}
+
+// Owns the demuxer's state (the PID -> handler `ProgramMap`, in particular)
+// but no I/O: packets are fed in one at a time via `push`, so callers can
+// source them from a file, a socket, a pipe, or partial buffers, and can
+// drive the demuxer incrementally as bytes arrive.
+pub struct Demultiplexer {
+    programs: ProgramMap,
+}
+
+impl Demultiplexer {
+    pub fn new(factory: ConsumerFactory) -> Demultiplexer {
+        let mut programs = ProgramMap::new();
+        programs.insert(0, ProgramSpecificInformation::new_pat(Rc::new(factory)));
+        Demultiplexer { programs }
+    }
+
+    /// Feeds a single TS packet to the demuxer. PAT/PMT updates and
+    /// elementary-stream payloads are dispatched to the handlers and
+    /// consumers registered via the `ConsumerFactory` passed to `new`.
+    pub fn push(&mut self, packet: &Packet) -> Result<(), DemuxError> {
+        let pid = get_pid(packet);
+        // Null-stuffing packets, used to pad the stream to a constant bit
+        // rate, carry no payload and are present in essentially every
+        // real-world capture: they are expected to be unregistered, not an
+        // error.
+        if pid == NULL_PID {
+            return Ok(());
+        }
+        let program = self.programs.get_mut(&pid).ok_or(DemuxError::UnknownPid(pid))?;
+        let update_programs = program.process(packet)?;
+        update_programs(&mut self.programs);
+        Ok(())
+    }
+
+    /// The PIDs currently registered with a handler (PAT/PMT sections or
+    /// elementary-stream programs), for incremental/streaming introspection.
+    pub fn active_pids(&self) -> Vec<u16> {
+        self.programs.keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference check value from the CRC-32/MPEG-2 catalogue entry (same
+    // parameters as the PSI CRC): digest of ASCII "123456789".
+    #[test]
+    fn crc32_mpeg2_matches_reference_check_value() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_e6e7);
+    }
+
+    // Encodes a 33-bit PTS/DTS value into the 5-byte marker-bit-interleaved
+    // form `parse_timestamp_90khz` decodes, the inverse of its bit layout.
+    fn encode_timestamp_90khz(v: u64) -> [u8; 5] {
+        [
+            0x01 | (((v >> 30) & 0x7) << 1) as u8,
+            ((v >> 22) & 0xFF) as u8,
+            0x01 | (((v >> 15) & 0x7F) << 1) as u8,
+            ((v >> 7) & 0xFF) as u8,
+            0x01 | ((v & 0x7F) << 1) as u8,
+        ]
+    }
+
+    #[test]
+    fn parse_timestamp_90khz_round_trips() {
+        let v: u64 = 0x1_5a5a5a5a & ((1u64 << 33) - 1);
+        assert_eq!(parse_timestamp_90khz(&encode_timestamp_90khz(v)), v);
+    }
+
+    #[test]
+    fn get_pts_dts_reads_pts_only() {
+        let mut pes = vec![0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0x80, 0x05];
+        pes.extend_from_slice(&encode_timestamp_90khz(123_456));
+        let (pts, dts) = get_pts_dts(&pes).unwrap();
+        assert_eq!(pts, Some(ClockRef::from_90khz(123_456)));
+        assert_eq!(dts, None);
+    }
+
+    #[test]
+    fn get_pts_dts_reads_pts_then_dts() {
+        let mut pes = vec![0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0xC0, 0x0A];
+        pes.extend_from_slice(&encode_timestamp_90khz(200_000));
+        pes.extend_from_slice(&encode_timestamp_90khz(190_000));
+        let (pts, dts) = get_pts_dts(&pes).unwrap();
+        assert_eq!(pts, Some(ClockRef::from_90khz(200_000)));
+        assert_eq!(dts, Some(ClockRef::from_90khz(190_000)));
+    }
+
+    #[test]
+    fn get_pcr_reads_base_and_extension() {
+        let base: u64 = 12_345;
+        let extension: u16 = 67;
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[3] = 0x30; // adaptation_field_control 0b11: adaptation field followed by payload
+        packet[4] = 7; // adaptation_field_length: flags + 6-byte PCR
+        packet[5] = 0x10; // PCR_flag
+        packet[6] = ((base >> 25) & 0xFF) as u8;
+        packet[7] = ((base >> 17) & 0xFF) as u8;
+        packet[8] = ((base >> 9) & 0xFF) as u8;
+        packet[9] = ((base >> 1) & 0xFF) as u8;
+        packet[10] = (((base & 1) as u8) << 7) | 0x7E | (((extension >> 8) & 1) as u8);
+        packet[11] = (extension & 0xFF) as u8;
+
+        assert_eq!(get_pcr(&packet), Some(ClockRef::from_pcr(base, extension)));
+    }
+}
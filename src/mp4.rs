@@ -0,0 +1,582 @@
+//
+// Minimal fragmented MP4 (ISO/IEC 14496-12) muxer.
+//
+// Builds just enough of ftyp/moov/moof/mdat to hold an AVC (H.264) and/or
+// an AAC track, one movie fragment per access unit, so the container can
+// be written incrementally as the demuxer produces samples rather than
+// buffering the whole program first.
+//
+
+use std::io::{self, Write};
+
+fn u16be(v: u16) -> [u8; 2] { v.to_be_bytes() }
+fn u32be(v: u32) -> [u8; 4] { v.to_be_bytes() }
+fn u64be(v: u64) -> [u8; 8] { v.to_be_bytes() }
+
+fn make_box(fourcc: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(8 + body.len());
+    b.extend_from_slice(&u32be((8 + body.len()) as u32));
+    b.extend_from_slice(fourcc);
+    b.extend_from_slice(body);
+    b
+}
+
+fn make_full_box(fourcc: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut full = Vec::with_capacity(4 + body.len());
+    full.push(version);
+    full.extend_from_slice(&u32be(flags)[1..]); // 24-bit flags
+    full.extend_from_slice(body);
+    make_box(fourcc, &full)
+}
+
+// A tiny MSB-first bit reader, just enough to decode the exp-Golomb
+// fields at the front of an SPS (up to picture dimensions).
+struct BitReader<'a> {
+    data: &'a [u8],
+    byte: usize,
+    bit: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, byte: 0, bit: 0 }
+    }
+
+    fn read_bit(&mut self) -> u32 {
+        let byte = *self.data.get(self.byte).unwrap_or(&0);
+        let bit = (byte >> (7 - self.bit)) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.byte += 1;
+        }
+        bit as u32
+    }
+
+    fn read_bits(&mut self, n: u8) -> u32 {
+        let mut v = 0;
+        for _ in 0..n {
+            v = (v << 1) | self.read_bit();
+        }
+        v
+    }
+
+    fn read_ue(&mut self) -> u32 {
+        let mut leading_zeros = 0;
+        // Capped at 31 (not 32) so `1u32 << leading_zeros` below can't
+        // overflow on a truncated/garbage SPS with a run-on zero prefix.
+        while self.read_bit() == 0 && leading_zeros < 31 {
+            leading_zeros += 1;
+        }
+        (1u32 << leading_zeros) - 1 + self.read_bits(leading_zeros as u8)
+    }
+
+    fn read_se(&mut self) -> i32 {
+        let ue = self.read_ue();
+        if ue.is_multiple_of(2) { -((ue / 2) as i32) } else { ue.div_ceil(2) as i32 }
+    }
+}
+
+// Derived from the first SPS/PPS NAL units seen on an H.264 elementary
+// stream: the raw bytes go into the `avcC` box, the dimensions parsed from
+// the SPS size the `avc1` sample entry and the track's `tkhd`.
+pub struct AvcConfig {
+    pub sps: Vec<u8>,
+    pub pps: Vec<u8>,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl AvcConfig {
+    pub fn from_sps_pps(sps: Vec<u8>, pps: Vec<u8>) -> AvcConfig {
+        let (width, height) = parse_sps_dimensions(&sps).unwrap_or((0, 0));
+        AvcConfig { sps, pps, width, height }
+    }
+
+    fn avcc_box(&self) -> Vec<u8> {
+        let mut body = vec![
+            0x01, // configurationVersion
+            self.sps.get(1).copied().unwrap_or(0), // AVCProfileIndication
+            self.sps.get(2).copied().unwrap_or(0), // profile_compatibility
+            self.sps.get(3).copied().unwrap_or(0), // AVCLevelIndication
+            0xFF, // reserved(6) + lengthSizeMinusOne=3 (4-byte NAL length)
+            0xE1, // reserved(3) + numOfSequenceParameterSets=1
+        ];
+        body.extend_from_slice(&u16be(self.sps.len() as u16));
+        body.extend_from_slice(&self.sps);
+        body.push(0x01); // numOfPictureParameterSets
+        body.extend_from_slice(&u16be(self.pps.len() as u16));
+        body.extend_from_slice(&self.pps);
+        make_box(b"avcC", &body)
+    }
+
+    fn sample_entry(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&[0; 6]); // reserved
+        body.extend_from_slice(&u16be(1)); // data_reference_index
+        body.extend_from_slice(&[0; 16]); // pre_defined + reserved
+        body.extend_from_slice(&u16be(self.width));
+        body.extend_from_slice(&u16be(self.height));
+        body.extend_from_slice(&u32be(0x00480000)); // horizresolution 72dpi
+        body.extend_from_slice(&u32be(0x00480000)); // vertresolution 72dpi
+        body.extend_from_slice(&u32be(0)); // reserved
+        body.extend_from_slice(&u16be(1)); // frame_count
+        body.extend_from_slice(&[0; 32]); // compressorname
+        body.extend_from_slice(&u16be(0x0018)); // depth
+        body.extend_from_slice(&[0xFF, 0xFF]); // pre_defined = -1
+        body.extend_from_slice(&self.avcc_box());
+        make_box(b"avc1", &body)
+    }
+}
+
+// Parses `pic_width`/`pic_height` out of a baseline/main-profile SPS
+// (no scaling lists), per ITU-T H.264 7.3.2.1.1.
+fn parse_sps_dimensions(sps: &[u8]) -> Option<(u16, u16)> {
+    let mut r = BitReader::new(&sps[1..]); // skip the NAL header byte
+    let _profile_idc = r.read_bits(8);
+    let _constraint_flags = r.read_bits(8);
+    let _level_idc = r.read_bits(8);
+    let _seq_parameter_set_id = r.read_ue();
+    let _log2_max_frame_num_minus4 = r.read_ue();
+    let pic_order_cnt_type = r.read_ue();
+    if pic_order_cnt_type == 0 {
+        let _log2_max_pic_order_cnt_lsb_minus4 = r.read_ue();
+    } else if pic_order_cnt_type == 1 {
+        let _delta_pic_order_always_zero = r.read_bit();
+        let _offset_for_non_ref_pic = r.read_se();
+        let _offset_for_top_to_bottom_field = r.read_se();
+        let num_ref_frames_in_pic_order_cnt_cycle = r.read_ue();
+        for _ in 0..num_ref_frames_in_pic_order_cnt_cycle {
+            let _offset_for_ref_frame = r.read_se();
+        }
+    }
+    let _max_num_ref_frames = r.read_ue();
+    let _gaps_in_frame_num_value_allowed_flag = r.read_bit();
+    let pic_width_in_mbs_minus1 = r.read_ue();
+    let pic_height_in_map_units_minus1 = r.read_ue();
+    let frame_mbs_only_flag = r.read_bit();
+    if frame_mbs_only_flag == 0 {
+        let _mb_adaptive_frame_field_flag = r.read_bit();
+    }
+    let _direct_8x8_inference_flag = r.read_bit();
+    let frame_crop_flag = r.read_bit();
+    let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+    if frame_crop_flag != 0 {
+        crop_left = r.read_ue();
+        crop_right = r.read_ue();
+        crop_top = r.read_ue();
+        crop_bottom = r.read_ue();
+    }
+
+    let width = (pic_width_in_mbs_minus1 + 1) * 16 - (crop_left + crop_right) * 2;
+    let height = (2 - frame_mbs_only_flag) * (pic_height_in_map_units_minus1 + 1) * 16
+        - (crop_top + crop_bottom) * 2;
+    Some((width as u16, height as u16))
+}
+
+// Derived from the fixed header of the first ADTS frame seen on an AAC
+// elementary stream (ISO/IEC 13818-7 Annex).
+pub struct AacConfig {
+    pub object_type: u8,
+    pub sampling_frequency_index: u8,
+    pub channel_config: u8,
+}
+
+impl AacConfig {
+    pub fn from_adts_header(header: &[u8]) -> Option<AacConfig> {
+        if header.len() < 7 || header[0] != 0xFF || header[1] & 0xF0 != 0xF0 {
+            return None;
+        }
+        let object_type = ((header[2] & 0xC0) >> 6) + 1; // AOT = profile + 1
+        let sampling_frequency_index = (header[2] & 0x3C) >> 2;
+        let channel_config = ((header[2] & 0x01) << 2) | ((header[3] & 0xC0) >> 6);
+        Some(AacConfig { object_type, sampling_frequency_index, channel_config })
+    }
+
+    // 2-byte AudioSpecificConfig as embedded in the `esds` box.
+    fn audio_specific_config(&self) -> [u8; 2] {
+        let v = (self.object_type as u16) << 11
+            | (self.sampling_frequency_index as u16) << 7
+            | (self.channel_config as u16) << 3;
+        u16be(v)
+    }
+
+    fn esds_box(&self) -> Vec<u8> {
+        let decoder_specific_info = self.audio_specific_config();
+        let mut decoder_config_descriptor = Vec::new();
+        decoder_config_descriptor.push(0x40); // objectTypeIndication: MPEG-4 AAC
+        decoder_config_descriptor.push(0x15); // streamType=audio(5)<<2 | upStream=0 | reserved=1
+        decoder_config_descriptor.extend_from_slice(&[0, 0, 0]); // bufferSizeDB
+        decoder_config_descriptor.extend_from_slice(&u32be(0)); // maxBitrate
+        decoder_config_descriptor.extend_from_slice(&u32be(0)); // avgBitrate
+        decoder_config_descriptor.push(0x05); // DecoderSpecificInfo tag
+        decoder_config_descriptor.push(decoder_specific_info.len() as u8);
+        decoder_config_descriptor.extend_from_slice(&decoder_specific_info);
+
+        let mut es_descriptor = Vec::new();
+        es_descriptor.extend_from_slice(&u16be(1)); // ES_ID
+        es_descriptor.push(0); // flags/streamPriority
+        es_descriptor.push(0x04); // DecoderConfigDescriptor tag
+        es_descriptor.push(decoder_config_descriptor.len() as u8);
+        es_descriptor.extend_from_slice(&decoder_config_descriptor);
+        es_descriptor.push(0x06); // SLConfigDescriptor tag
+        es_descriptor.push(1);
+        es_descriptor.push(0x02); // predefined = MP4
+
+        let mut body = Vec::new();
+        body.push(0x03); // ES_DescriptorTag
+        body.push(es_descriptor.len() as u8);
+        body.extend_from_slice(&es_descriptor);
+        make_full_box(b"esds", 0, 0, &body)
+    }
+
+    fn sample_entry(&self) -> Vec<u8> {
+        let channels = if self.channel_config == 0 { 2 } else { self.channel_config as u16 };
+        let mut body = Vec::new();
+        body.extend_from_slice(&[0; 6]); // reserved
+        body.extend_from_slice(&u16be(1)); // data_reference_index
+        body.extend_from_slice(&u32be(0)); // reserved
+        body.extend_from_slice(&u32be(0));
+        body.extend_from_slice(&u16be(channels));
+        body.extend_from_slice(&u16be(16)); // samplesize
+        body.extend_from_slice(&u32be(0)); // pre_defined + reserved
+        body.extend_from_slice(&u32be(sampling_frequency_hz(self.sampling_frequency_index) << 16));
+        body.extend_from_slice(&self.esds_box());
+        make_box(b"mp4a", &body)
+    }
+}
+
+fn sampling_frequency_hz(index: u8) -> u32 {
+    const TABLE: [u32; 13] = [
+        96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+    ];
+    TABLE.get(index as usize).copied().unwrap_or(44100)
+}
+
+pub enum TrackConfig {
+    Avc(AvcConfig),
+    Aac(AacConfig),
+}
+
+impl TrackConfig {
+    fn handler_type(&self) -> &'static [u8; 4] {
+        match self {
+            TrackConfig::Avc(_) => b"vide",
+            TrackConfig::Aac(_) => b"soun",
+        }
+    }
+
+    fn sample_entry(&self) -> Vec<u8> {
+        match self {
+            TrackConfig::Avc(c) => c.sample_entry(),
+            TrackConfig::Aac(c) => c.sample_entry(),
+        }
+    }
+
+    fn is_audio(&self) -> bool {
+        matches!(self, TrackConfig::Aac(_))
+    }
+}
+
+// One access unit: the payload of a single PES packet, with its duration
+// in the track's timescale (derived from consecutive PTS values).
+pub struct Sample {
+    pub data: Vec<u8>,
+    pub duration: u32,
+    pub is_keyframe: bool,
+}
+
+struct Track {
+    id: u32,
+    timescale: u32,
+    config: Option<TrackConfig>,
+    next_decode_time: u64,
+}
+
+pub struct FragmentedMp4Writer<W: Write> {
+    writer: W,
+    tracks: Vec<Track>,
+    sequence_number: u32,
+    header_written: bool,
+    pending_fragments: Vec<u8>,
+}
+
+impl<W: Write> FragmentedMp4Writer<W> {
+    pub fn new(writer: W) -> FragmentedMp4Writer<W> {
+        FragmentedMp4Writer {
+            writer,
+            tracks: Vec::new(),
+            sequence_number: 0,
+            header_written: false,
+            pending_fragments: Vec::new(),
+        }
+    }
+
+    pub fn register_track(&mut self, timescale: u32) -> u32 {
+        let id = self.tracks.len() as u32 + 1;
+        self.tracks.push(Track { id, timescale, config: None, next_decode_time: 0 });
+        id
+    }
+
+    pub fn set_track_config(&mut self, track_id: u32, config: TrackConfig) -> io::Result<()> {
+        let track = self.tracks.iter_mut().find(|t| t.id == track_id).expect("unknown track");
+        track.config = Some(config);
+
+        if !self.header_written && self.tracks.iter().all(|t| t.config.is_some()) {
+            let header = make_ftyp_and_moov(&self.tracks);
+            self.writer.write_all(&header)?;
+            self.writer.write_all(&self.pending_fragments)?;
+            self.pending_fragments.clear();
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    pub fn write_sample(&mut self, track_id: u32, sample: Sample) -> io::Result<()> {
+        self.sequence_number += 1;
+        let track = self.tracks.iter_mut().find(|t| t.id == track_id).expect("unknown track");
+        let fragment = make_fragment(track.id, track.next_decode_time, self.sequence_number, &sample);
+        track.next_decode_time += sample.duration as u64;
+
+        if self.header_written {
+            self.writer.write_all(&fragment)
+        } else {
+            self.pending_fragments.extend_from_slice(&fragment);
+            Ok(())
+        }
+    }
+}
+
+fn make_ftyp_and_moov(tracks: &[Track]) -> Vec<u8> {
+    let mut out = make_ftyp();
+    out.extend_from_slice(&make_moov(tracks));
+    out
+}
+
+fn make_ftyp() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(b"isom"); // major_brand
+    body.extend_from_slice(&u32be(0)); // minor_version
+    body.extend_from_slice(b"isom");
+    body.extend_from_slice(b"iso5");
+    body.extend_from_slice(b"avc1");
+    body.extend_from_slice(b"mp42");
+    make_box(b"ftyp", &body)
+}
+
+const IDENTITY_MATRIX: [u8; 36] = [
+    0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00,
+];
+
+fn make_moov(tracks: &[Track]) -> Vec<u8> {
+    let mut body = make_mvhd(tracks.len() as u32 + 1);
+    for track in tracks {
+        body.extend_from_slice(&make_trak(track));
+    }
+    body.extend_from_slice(&make_mvex(tracks));
+    make_box(b"moov", &body)
+}
+
+fn make_mvhd(next_track_id: u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&u32be(0)); // creation_time
+    body.extend_from_slice(&u32be(0)); // modification_time
+    body.extend_from_slice(&u32be(1000)); // timescale
+    body.extend_from_slice(&u32be(0)); // duration (unknown, fragmented)
+    body.extend_from_slice(&u32be(0x00010000)); // rate 1.0
+    body.extend_from_slice(&u16be(0x0100)); // volume 1.0
+    body.extend_from_slice(&[0; 10]); // reserved
+    body.extend_from_slice(&IDENTITY_MATRIX);
+    body.extend_from_slice(&[0; 24]); // pre_defined
+    body.extend_from_slice(&u32be(next_track_id));
+    make_full_box(b"mvhd", 0, 0, &body)
+}
+
+fn make_trak(track: &Track) -> Vec<u8> {
+    let config = track.config.as_ref().expect("track must be configured before writing moov");
+
+    let mut tkhd_body = Vec::new();
+    tkhd_body.extend_from_slice(&u32be(0)); // creation_time
+    tkhd_body.extend_from_slice(&u32be(0)); // modification_time
+    tkhd_body.extend_from_slice(&u32be(track.id));
+    tkhd_body.extend_from_slice(&u32be(0)); // reserved
+    tkhd_body.extend_from_slice(&u32be(0)); // duration (unknown, fragmented)
+    tkhd_body.extend_from_slice(&[0; 8]); // reserved
+    tkhd_body.extend_from_slice(&u16be(0)); // layer
+    tkhd_body.extend_from_slice(&u16be(0)); // alternate_group
+    tkhd_body.extend_from_slice(&u16be(if config.is_audio() { 0x0100 } else { 0 })); // volume
+    tkhd_body.extend_from_slice(&[0; 2]); // reserved
+    tkhd_body.extend_from_slice(&IDENTITY_MATRIX);
+    let (width, height) = match config {
+        TrackConfig::Avc(c) => (c.width, c.height),
+        TrackConfig::Aac(_) => (0, 0),
+    };
+    tkhd_body.extend_from_slice(&u32be((width as u32) << 16));
+    tkhd_body.extend_from_slice(&u32be((height as u32) << 16));
+    let tkhd = make_full_box(b"tkhd", 0, 0x000007, &tkhd_body); // enabled+in_movie+in_preview
+
+    let mut mdhd_body = Vec::new();
+    mdhd_body.extend_from_slice(&u32be(0)); // creation_time
+    mdhd_body.extend_from_slice(&u32be(0)); // modification_time
+    mdhd_body.extend_from_slice(&u32be(track.timescale));
+    mdhd_body.extend_from_slice(&u32be(0)); // duration (unknown, fragmented)
+    mdhd_body.extend_from_slice(&u16be(0x55C4)); // language: und
+    mdhd_body.extend_from_slice(&u16be(0)); // pre_defined
+    let mdhd = make_full_box(b"mdhd", 0, 0, &mdhd_body);
+
+    let mut hdlr_body = Vec::new();
+    hdlr_body.extend_from_slice(&u32be(0)); // pre_defined
+    hdlr_body.extend_from_slice(config.handler_type());
+    hdlr_body.extend_from_slice(&[0; 12]); // reserved
+    hdlr_body.extend_from_slice(b"\0"); // name
+    let hdlr = make_full_box(b"hdlr", 0, 0, &hdlr_body);
+
+    let media_header_box = if config.is_audio() {
+        make_full_box(b"smhd", 0, 0, &[0, 0, 0, 0])
+    } else {
+        make_full_box(b"vmhd", 0, 1, &[0, 0, 0, 0, 0, 0, 0, 0])
+    };
+
+    let dref_entry = make_full_box(b"url ", 0, 0x000001, &[]);
+    let mut dref_body = Vec::new();
+    dref_body.extend_from_slice(&u32be(1));
+    dref_body.extend_from_slice(&dref_entry);
+    let dref = make_full_box(b"dref", 0, 0, &dref_body);
+    let dinf = make_box(b"dinf", &dref);
+
+    let mut stsd_body = Vec::new();
+    stsd_body.extend_from_slice(&u32be(1)); // entry_count
+    stsd_body.extend_from_slice(&config.sample_entry());
+    let stsd = make_full_box(b"stsd", 0, 0, &stsd_body);
+    let stts = make_full_box(b"stts", 0, 0, &u32be(0));
+    let stsc = make_full_box(b"stsc", 0, 0, &u32be(0));
+    let stsz = make_full_box(b"stsz", 0, 0, &[&u32be(0)[..], &u32be(0)[..]].concat());
+    let stco = make_full_box(b"stco", 0, 0, &u32be(0));
+    let mut stbl_body = Vec::new();
+    stbl_body.extend_from_slice(&stsd);
+    stbl_body.extend_from_slice(&stts);
+    stbl_body.extend_from_slice(&stsc);
+    stbl_body.extend_from_slice(&stsz);
+    stbl_body.extend_from_slice(&stco);
+    let stbl = make_box(b"stbl", &stbl_body);
+
+    let mut minf_body = Vec::new();
+    minf_body.extend_from_slice(&media_header_box);
+    minf_body.extend_from_slice(&dinf);
+    minf_body.extend_from_slice(&stbl);
+    let minf = make_box(b"minf", &minf_body);
+
+    let mut mdia_body = Vec::new();
+    mdia_body.extend_from_slice(&mdhd);
+    mdia_body.extend_from_slice(&hdlr);
+    mdia_body.extend_from_slice(&minf);
+    let mdia = make_box(b"mdia", &mdia_body);
+
+    let mut trak_body = Vec::new();
+    trak_body.extend_from_slice(&tkhd);
+    trak_body.extend_from_slice(&mdia);
+    make_box(b"trak", &trak_body)
+}
+
+fn make_mvex(tracks: &[Track]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for track in tracks {
+        let mut trex_body = Vec::new();
+        trex_body.extend_from_slice(&u32be(track.id));
+        trex_body.extend_from_slice(&u32be(1)); // default_sample_description_index
+        trex_body.extend_from_slice(&u32be(0)); // default_sample_duration
+        trex_body.extend_from_slice(&u32be(0)); // default_sample_size
+        trex_body.extend_from_slice(&u32be(0)); // default_sample_flags
+        body.extend_from_slice(&make_full_box(b"trex", 0, 0, &trex_body));
+    }
+    make_box(b"mvex", &body)
+}
+
+fn make_fragment(track_id: u32, base_decode_time: u64, sequence_number: u32, sample: &Sample) -> Vec<u8> {
+    let mut mfhd_body = Vec::new();
+    mfhd_body.extend_from_slice(&u32be(sequence_number));
+    let mfhd = make_full_box(b"mfhd", 0, 0, &mfhd_body);
+
+    let mut tfhd_body = Vec::new();
+    tfhd_body.extend_from_slice(&u32be(track_id));
+    // default-base-is-moof
+    let tfhd = make_full_box(b"tfhd", 0, 0x020000, &tfhd_body);
+
+    let mut tfdt_body = Vec::new();
+    tfdt_body.extend_from_slice(&u64be(base_decode_time));
+    let tfdt = make_full_box(b"tfdt", 1, 0, &tfdt_body);
+
+    // data-offset-present | sample-duration-present | sample-size-present | sample-flags-present
+    let trun_flags = 0x000001 | 0x000100 | 0x000200 | 0x000400;
+    let sample_flags: u32 = if sample.is_keyframe { 0x02000000 } else { 0x01010000 };
+    let mut trun_body = Vec::new();
+    trun_body.extend_from_slice(&u32be(1)); // sample_count
+    trun_body.extend_from_slice(&u32be(0)); // data_offset, patched once moof's length is known
+    trun_body.extend_from_slice(&u32be(sample.duration));
+    trun_body.extend_from_slice(&u32be(sample.data.len() as u32));
+    trun_body.extend_from_slice(&u32be(sample_flags));
+    let trun = make_full_box(b"trun", 0, trun_flags, &trun_body);
+
+    let mut traf_body = Vec::new();
+    traf_body.extend_from_slice(&tfhd);
+    traf_body.extend_from_slice(&tfdt);
+    traf_body.extend_from_slice(&trun);
+    let traf = make_box(b"traf", &traf_body);
+
+    let mut moof_body = Vec::new();
+    moof_body.extend_from_slice(&mfhd);
+    moof_body.extend_from_slice(&traf);
+    let mut moof = make_box(b"moof", &moof_body);
+
+    // data_offset is measured from the start of the moof box to the start
+    // of this sample's data, which immediately follows the mdat header.
+    let data_offset = moof.len() as u32 + 8;
+    // trun's data_offset field sits 16 bytes (8-byte box header + 4-byte
+    // version/flags + 4-byte sample_count) past the start of trun, which
+    // is the last box inside moof.
+    let data_offset_pos = moof.len() - trun.len() + 16;
+    moof[data_offset_pos..data_offset_pos + 4].copy_from_slice(&u32be(data_offset));
+
+    let mdat = make_box(b"mdat", &sample.data);
+    moof.extend_from_slice(&mdat);
+    moof
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_reader_read_ue_decodes_exp_golomb_codes() {
+        assert_eq!(BitReader::new(&[0b1000_0000]).read_ue(), 0);
+        assert_eq!(BitReader::new(&[0b0100_0000]).read_ue(), 1);
+        assert_eq!(BitReader::new(&[0b0110_0000]).read_ue(), 2);
+    }
+
+    // chunk0-4 review fix: a run-on prefix of zero bits (as produced by a
+    // truncated/garbage SPS) must not panic with a shift/add overflow.
+    #[test]
+    fn bit_reader_read_ue_does_not_panic_on_all_zero_input() {
+        let zeros = [0u8; 8];
+        BitReader::new(&zeros).read_ue();
+    }
+
+    // chunk0-4 review fix: trun's data_offset must point past moof, at the
+    // start of the sample bytes inside mdat, not at some earlier field.
+    #[test]
+    fn make_fragment_trun_data_offset_points_into_mdat() {
+        let sample = Sample { data: vec![1, 2, 3, 4], duration: 1_000, is_keyframe: true };
+        let fragment = make_fragment(1, 0, 1, &sample);
+
+        let trun_fourcc = fragment.windows(4).position(|w| w == b"trun").unwrap();
+        let data_offset_pos = trun_fourcc + 4 + 4 + 4; // fourcc + version/flags + sample_count
+        let data_offset = (fragment[data_offset_pos] as usize) << 24
+            | (fragment[data_offset_pos + 1] as usize) << 16
+            | (fragment[data_offset_pos + 2] as usize) << 8
+            | (fragment[data_offset_pos + 3] as usize);
+
+        assert_eq!(&fragment[data_offset .. data_offset + sample.data.len()], &sample.data[..]);
+    }
+}
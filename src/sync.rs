@@ -0,0 +1,210 @@
+//
+// Auto-detection and resynchronization for the transport stream packet
+// layouts found in the wild: plain 188-byte packets, 192-byte M2TS packets
+// (4-byte arrival timestamp prefix, used by Blu-ray), and 204-byte FEC
+// packets (16-byte Reed-Solomon trailer, common in broadcast captures).
+//
+// Packets always start with a 0x47 sync byte recurring every `stride`
+// bytes; the extra bytes in the 192/204 layouts just ride along between
+// packets and are skipped without needing to be interpreted.
+//
+
+use std::io::{self, Read};
+
+use crate::{Packet, PACKET_SIZE};
+
+const CANDIDATE_STRIDES: [usize; 3] = [188, 192, 204];
+const SYNC_BYTE: u8 = 0x47;
+
+// How many consecutive sync bytes, `stride` bytes apart, must be found
+// before a stride is trusted during detection or resync.
+const CONFIRMATIONS: usize = 4;
+
+const FILL_CHUNK: usize = 32 * 204;
+const DETECTION_WINDOW: usize = 16 * 204;
+
+fn find_sync_run(buf: &[u8], stride: usize, start: usize) -> bool {
+    (0 .. CONFIRMATIONS).all(|i| {
+        let pos = start + i * stride;
+        pos < buf.len() && buf[pos] == SYNC_BYTE
+    })
+}
+
+// Scans `buf` for the packet stride and the position of the first sync
+// byte, trying each candidate stride until a run of `CONFIRMATIONS`
+// consecutive sync bytes is found.
+fn detect(buf: &[u8]) -> Option<(usize, usize)> {
+    for &stride in &CANDIDATE_STRIDES {
+        let reach = (CONFIRMATIONS - 1) * stride;
+        if buf.len() <= reach {
+            continue;
+        }
+        let max_start = buf.len() - reach;
+        for start in 0 .. max_start {
+            if buf[start] == SYNC_BYTE && find_sync_run(buf, stride, start) {
+                return Some((stride, start));
+            }
+        }
+    }
+    None
+}
+
+/// Reads raw TS packets from `reader`, auto-detecting the 188/192/204-byte
+/// layout up front and resyncing past corrupted regions instead of letting
+/// a single lost sync byte cascade into failure for the rest of the stream.
+pub struct PacketSource<R> {
+    reader: R,
+    stride: usize,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> PacketSource<R> {
+    pub fn new(mut reader: R) -> io::Result<PacketSource<R>> {
+        let mut buf = Vec::new();
+        let mut eof = false;
+        while buf.len() < DETECTION_WINDOW && !eof {
+            let mut chunk = [0u8; FILL_CHUNK];
+            let n = reader.read(&mut chunk)?;
+            if n == 0 {
+                eof = true;
+                break;
+            }
+            buf.extend_from_slice(&chunk[.. n]);
+        }
+
+        let (stride, start) = detect(&buf).unwrap_or((PACKET_SIZE, 0));
+        buf.drain(.. start);
+        Ok(PacketSource { reader, stride, buf, eof })
+    }
+
+    fn fill(&mut self, min_len: usize) -> io::Result<()> {
+        while !self.eof && self.buf.len() < min_len {
+            let mut chunk = [0u8; FILL_CHUNK];
+            let n = self.reader.read(&mut chunk)?;
+            if n == 0 {
+                self.eof = true;
+                break;
+            }
+            self.buf.extend_from_slice(&chunk[.. n]);
+        }
+        Ok(())
+    }
+
+    // Scans forward for the next point where the sync byte recurs at the
+    // detected stride, dropping everything before it.
+    fn resync(&mut self) -> io::Result<()> {
+        loop {
+            self.fill(self.stride * CONFIRMATIONS)?;
+
+            let found = (0 .. self.buf.len()).find(|&i| {
+                self.buf[i] == SYNC_BYTE && find_sync_run(&self.buf, self.stride, i)
+            });
+            if let Some(pos) = found {
+                if pos > 0 {
+                    eprintln!("  WARN: lost sync, resynced after {} bytes", pos);
+                }
+                self.buf.drain(.. pos);
+                return Ok(());
+            }
+            if self.eof {
+                self.buf.clear();
+                return Ok(());
+            }
+
+            // Nothing found in the buffered window yet: drop all but a
+            // stride's worth so the next fill brings in fresh bytes to scan.
+            let drop_len = self.buf.len().saturating_sub(self.stride);
+            if drop_len == 0 {
+                return Ok(());
+            }
+            self.buf.drain(.. drop_len);
+        }
+    }
+
+    /// Returns the next demuxable TS packet, or `None` at end of stream.
+    pub fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        self.fill(self.stride)?;
+        // The trailing bytes of a stride are either the M2TS timestamp of
+        // the *next* packet, which the very last packet in the stream never
+        // has, or FEC parity, which a truncated capture may lack: only
+        // require a full packet's worth of bytes, not a full stride.
+        if self.buf.len() < PACKET_SIZE {
+            return Ok(None);
+        }
+        if self.buf[0] != SYNC_BYTE {
+            self.resync()?;
+            if self.buf.len() < PACKET_SIZE {
+                return Ok(None);
+            }
+        }
+
+        let mut packet = [0u8; PACKET_SIZE];
+        packet.copy_from_slice(&self.buf[.. PACKET_SIZE]);
+        let drop = self.stride.min(self.buf.len());
+        self.buf.drain(.. drop);
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds `count` synthetic records of `stride` bytes: `prefix` filler
+    // bytes, then a 188-byte TS packet (sync byte + an index marker in
+    // byte 1), then whatever filler is left to pad out to `stride`.
+    fn build_stream(stride: usize, prefix: usize, count: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0 .. count {
+            data.extend(vec![0xAAu8; prefix]);
+            let mut packet = vec![0u8; PACKET_SIZE];
+            packet[0] = SYNC_BYTE;
+            packet[1] = (i % 256) as u8;
+            data.extend_from_slice(&packet);
+            data.extend(vec![0xBBu8; stride - prefix - PACKET_SIZE]);
+        }
+        data
+    }
+
+    #[test]
+    fn detects_plain_188_byte_layout() {
+        let data = build_stream(188, 0, 20);
+        let mut source = PacketSource::new(&data[..]).unwrap();
+        assert_eq!(source.stride, 188);
+
+        let mut count = 0;
+        while let Some(packet) = source.next_packet().unwrap() {
+            assert_eq!(packet[0], SYNC_BYTE);
+            count += 1;
+        }
+        assert_eq!(count, 20);
+    }
+
+    #[test]
+    fn detects_m2ts_192_byte_layout_and_skips_the_prefix() {
+        let data = build_stream(192, 4, 20);
+        let mut source = PacketSource::new(&data[..]).unwrap();
+        assert_eq!(source.stride, 192);
+
+        let mut indices = Vec::new();
+        while let Some(packet) = source.next_packet().unwrap() {
+            indices.push(packet[1]);
+        }
+        assert_eq!(indices, (0u8 .. 20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn resyncs_past_a_corrupted_packet() {
+        let mut data = build_stream(204, 0, 10);
+        data[5 * 204] = 0x00; // destroy the 6th packet's sync byte
+        let mut source = PacketSource::new(&data[..]).unwrap();
+
+        let mut indices = Vec::new();
+        while let Some(packet) = source.next_packet().unwrap() {
+            indices.push(packet[1]);
+        }
+        // The corrupted packet (index 5) is dropped; the rest still parse.
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 6, 7, 8, 9]);
+    }
+}